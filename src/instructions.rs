@@ -1,5 +1,6 @@
 //! Low level functions for special x86 instructions.
 
+use core::sync::atomic::{compiler_fence, Ordering};
 use segmentation;
 
 /// Enable hardware interrupts using the `sti` instruction.
@@ -12,6 +13,72 @@ pub unsafe fn disable_interrupts() {
     asm!("cli");
 }
 
+/// Flags stored in the `RFLAGS` register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RFlags(u64);
+
+impl RFlags {
+    /// The interrupt-enable flag (bit 9): hardware interrupts are enabled when set.
+    pub const INTERRUPT_FLAG: RFlags = RFlags(1 << 9);
+
+    /// Returns the raw bit pattern.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns whether `self` contains all bits set in `other`.
+    pub fn contains(self, other: RFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Reads the current value of the `RFLAGS` register.
+pub fn read_flags() -> RFlags {
+    let flags: u64;
+    unsafe {
+        asm!("pushfq
+              pop $0"
+             : "=r" (flags) :: "memory" : "volatile");
+    }
+    RFlags(flags)
+}
+
+/// Returns whether hardware interrupts are currently enabled.
+pub fn interrupts_enabled() -> bool {
+    read_flags().contains(RFlags::INTERRUPT_FLAG)
+}
+
+/// Disables interrupts, runs `f`, and restores the previous interrupt state
+/// afterwards (rather than unconditionally re-enabling them), so nested
+/// calls don't turn interrupts back on too early.
+///
+/// A `compiler_fence` brackets the closure so the optimizer cannot hoist
+/// memory accesses across the `cli`/`sti` boundary.
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let saved_intpt_flag = interrupts_enabled();
+
+    if saved_intpt_flag {
+        unsafe {
+            disable_interrupts();
+        }
+    }
+
+    compiler_fence(Ordering::SeqCst);
+    let ret = f();
+    compiler_fence(Ordering::SeqCst);
+
+    if saved_intpt_flag {
+        unsafe {
+            enable_interrupts();
+        }
+    }
+
+    ret
+}
+
 /// Generate a software interrupt.
 /// This is a macro because the argument needs to be an immediate.
 #[macro_export]
@@ -98,6 +165,54 @@ pub fn rdtscp() -> u64 {
     ((high as u64) << 32) | (low as u64)
 }
 
+/// Read the time stamp counter, fenced with `LFENCE` so it is only executed
+/// after all previous instructions have completed locally.
+///
+/// This is the `LFENCE;RDTSC` sequence the `rdtsc` docs above recommend,
+/// spelled out here so callers don't have to hand-roll the fence themselves.
+pub fn rdtsc_serialized() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("lfence
+              rdtsc"
+             : "={eax}" (low), "={edx}" (high) ::: "volatile");
+    }
+    ((u64::from(high)) << 32) | (u64::from(low))
+}
+
+/// Read the time stamp counter using `RDTSCP`, followed by an `LFENCE` so
+/// that subsequent instructions cannot begin executing before the read has
+/// completed either.
+pub fn rdtscp_serialized() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtscp
+              lfence"
+             : "={eax}" (low), "={edx}" (high) :: "ecx" : "volatile");
+    }
+    ((u64::from(high)) << 32) | (u64::from(low))
+}
+
+/// Executes the `pause` instruction, signalling to the CPU that this is a
+/// spin-wait loop. On SMT cores this frees up execution resources for the
+/// other hyperthread, and it generally reduces the power used while spinning.
+///
+/// Also usable as `spin_loop_hint`.
+#[inline(always)]
+pub fn pause() {
+    unsafe {
+        asm!("pause" :::: "volatile");
+    }
+}
+
+/// An alias for [`pause`], matching the naming used elsewhere for spin-loop hints.
+#[inline(always)]
+pub fn spin_loop_hint() {
+    pause();
+}
+
 // Model specific registers
 
 /// Write 64 bits to msr register.
@@ -116,8 +231,359 @@ pub fn rdmsr(msr: u32) -> u64 {
     ((high as u64) << 32) | (low as u64)
 }
 
+/// CPU feature detection via the `cpuid` instruction.
+pub mod cpuid {
+    /// The four 32-bit registers returned by the `cpuid` instruction.
+    #[derive(Debug, Clone, Copy)]
+    pub struct CpuidResult {
+        pub eax: u32,
+        pub ebx: u32,
+        pub ecx: u32,
+        pub edx: u32,
+    }
+
+    /// Executes `cpuid` for the given leaf (passed in EAX), with ECX cleared
+    /// to zero.
+    pub fn cpuid(leaf: u32) -> CpuidResult {
+        cpuid_count(leaf, 0)
+    }
+
+    /// Executes `cpuid` for the given leaf (EAX) and sub-leaf (ECX).
+    ///
+    /// `ebx` is clobbered by `cpuid`, but LLVM reserves it for its own use in
+    /// position-independent code, so it is saved on the stack and restored
+    /// around the instruction instead of being passed as a normal output.
+    pub fn cpuid_count(leaf: u32, subleaf: u32) -> CpuidResult {
+        let eax: u32;
+        let ebx: u32;
+        let ecx: u32;
+        let edx: u32;
+        unsafe {
+            asm!("push %rbx
+                  cpuid
+                  mov %ebx, $1
+                  pop %rbx"
+                 : "={eax}" (eax), "=r" (ebx), "={ecx}" (ecx), "={edx}" (edx)
+                 : "{eax}" (leaf), "{ecx}" (subleaf)
+                 : "ebx"
+                 : "volatile");
+        }
+        CpuidResult { eax, ebx, ecx, edx }
+    }
+
+    /// Feature flags reported by `cpuid` leaf 1.
+    #[derive(Debug, Clone, Copy)]
+    pub struct FeatureInfo {
+        result: CpuidResult,
+    }
+
+    impl FeatureInfo {
+        /// Whether the time stamp counter and the `RDTSC` instruction are supported.
+        pub fn has_tsc(&self) -> bool {
+            self.result.edx & (1 << 4) != 0
+        }
+
+        /// Whether model specific registers and the `RDMSR`/`WRMSR` instructions are supported.
+        pub fn has_msr(&self) -> bool {
+            self.result.edx & (1 << 5) != 0
+        }
+
+        /// Whether an on-chip APIC is present.
+        pub fn has_apic(&self) -> bool {
+            self.result.edx & (1 << 9) != 0
+        }
+
+        /// Whether the `RDTSCP` instruction is supported.
+        ///
+        /// This bit is reported in extended leaf `0x8000_0001`, not in leaf 1,
+        /// so it is fetched separately from the other flags on this struct.
+        pub fn has_rdtscp(&self) -> bool {
+            cpuid(0x8000_0001).edx & (1 << 27) != 0
+        }
+    }
+
+    /// Returns the feature flags reported by `cpuid` leaf 1.
+    pub fn feature_info() -> FeatureInfo {
+        FeatureInfo { result: cpuid(1) }
+    }
+
+    /// Returns the 12-byte ASCII vendor ID string from `cpuid` leaf 0
+    /// (e.g. `b"GenuineIntel"` or `b"AuthenticAMD"`).
+    pub fn vendor_string() -> [u8; 12] {
+        let result = cpuid(0);
+        let mut vendor = [0u8; 12];
+        for (i, reg) in [result.ebx, result.edx, result.ecx].iter().enumerate() {
+            vendor[i * 4] = *reg as u8;
+            vendor[i * 4 + 1] = (*reg >> 8) as u8;
+            vendor[i * 4 + 2] = (*reg >> 16) as u8;
+            vendor[i * 4 + 3] = (*reg >> 24) as u8;
+        }
+        vendor
+    }
+}
+
+/// Control register (CR0/CR2/CR3/CR4) accessors.
+pub mod controlregs {
+    macro_rules! flags_type {
+        ($name:ident) => {
+            /// A set of bit flags backed by a 64-bit register value.
+            #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+            pub struct $name(u64);
+
+            impl $name {
+                /// Returns a value with no bits set.
+                pub const fn empty() -> $name {
+                    $name(0)
+                }
+
+                /// Returns the raw bit pattern.
+                pub const fn bits(self) -> u64 {
+                    self.0
+                }
+
+                /// Returns whether `self` contains all bits set in `other`.
+                pub fn contains(self, other: $name) -> bool {
+                    self.0 & other.0 == other.0
+                }
+            }
+
+            impl ::core::ops::BitOr for $name {
+                type Output = $name;
+                fn bitor(self, rhs: $name) -> $name {
+                    $name(self.0 | rhs.0)
+                }
+            }
+
+            impl ::core::ops::BitAnd for $name {
+                type Output = $name;
+                fn bitand(self, rhs: $name) -> $name {
+                    $name(self.0 & rhs.0)
+                }
+            }
+        };
+    }
+
+    flags_type!(Cr0Flags);
+    impl Cr0Flags {
+        /// Enables protected mode.
+        pub const PROTECTED_MODE: Cr0Flags = Cr0Flags(1);
+        /// Globally disables the caches.
+        pub const CACHE_DISABLE: Cr0Flags = Cr0Flags(1 << 30);
+        /// Not write-through: when set, disables write-through caching.
+        pub const NOT_WRITE_THROUGH: Cr0Flags = Cr0Flags(1 << 29);
+        /// Enables paging.
+        pub const PAGING: Cr0Flags = Cr0Flags(1 << 31);
+    }
+
+    flags_type!(Cr4Flags);
+    impl Cr4Flags {
+        /// Disables the `RDTSC`/`RDTSCP` instructions outside of ring 0.
+        pub const TIME_STAMP_DISABLE: Cr4Flags = Cr4Flags(1 << 2);
+    }
+
+    /// Returns the current value of the CR0 register.
+    pub unsafe fn cr0() -> Cr0Flags {
+        let value: u64;
+        asm!("mov %cr0, $0" : "=r" (value));
+        Cr0Flags(value)
+    }
+
+    /// Writes a new value to the CR0 register.
+    pub unsafe fn cr0_write(flags: Cr0Flags) {
+        asm!("mov $0, %cr0" :: "r" (flags.bits()) : "memory");
+    }
+
+    /// Returns the faulting linear address stored in CR2 after a page fault.
+    pub unsafe fn cr2() -> u64 {
+        let value: u64;
+        asm!("mov %cr2, $0" : "=r" (value));
+        value
+    }
+
+    /// The value of the CR3 register: the physical base address of the
+    /// active page-table hierarchy, plus the process-context identifier
+    /// (PCID) if PCIDs are enabled.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr3 {
+        /// Physical, page-aligned address of the level 4 page table.
+        pub page_table_base: u64,
+        /// Process-context identifier (bits 0..12 of CR3), if PCIDs are enabled.
+        pub pcid: u16,
+    }
+
+    /// Returns the current value of the CR3 register.
+    pub unsafe fn cr3() -> Cr3 {
+        let value: u64;
+        asm!("mov %cr3, $0" : "=r" (value));
+        Cr3 {
+            page_table_base: value & 0xffff_ffff_ffff_f000,
+            pcid: (value & 0xfff) as u16,
+        }
+    }
+
+    /// Writes a new page-table base address and PCID to the CR3 register.
+    pub unsafe fn cr3_write(cr3: Cr3) {
+        let value = (cr3.page_table_base & 0xffff_ffff_ffff_f000) | u64::from(cr3.pcid & 0xfff);
+        asm!("mov $0, %cr3" :: "r" (value) : "memory");
+    }
+
+    /// Returns the current value of the CR4 register.
+    pub unsafe fn cr4() -> Cr4Flags {
+        let value: u64;
+        asm!("mov %cr4, $0" : "=r" (value));
+        Cr4Flags(value)
+    }
+
+    /// Writes a new value to the CR4 register.
+    pub unsafe fn cr4_write(flags: Cr4Flags) {
+        asm!("mov $0, %cr4" :: "r" (flags.bits()) : "memory");
+    }
+}
+
+/// TLB (translation lookaside buffer) management.
+pub mod tlb {
+    use super::controlregs;
+
+    /// The virtual address of a page whose translation should be invalidated.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct VirtualAddress(pub u64);
+
+    /// Invalidates the TLB entry for the given virtual address using the
+    /// `invlpg` instruction, leaving all other entries untouched.
+    pub unsafe fn flush(addr: VirtualAddress) {
+        asm!("invlpg ($0)" :: "r" (addr.0) : "memory" : "volatile");
+    }
+
+    /// Flushes the entire TLB by reloading CR3 with its current value.
+    pub unsafe fn flush_all() {
+        controlregs::cr3_write(controlregs::cr3());
+    }
+}
+
 /// I/O port functionality.
 pub mod port {
+    use core::marker::PhantomData;
+
+    /// A trait implemented by the integer widths usable with the `in`/`out`
+    /// instructions. Sealed so that third-party code cannot add further
+    /// implementations.
+    pub trait PortValue: private::Sealed {
+        /// Reads a value of this width from the given port.
+        unsafe fn port_read(port: u16) -> Self;
+
+        /// Writes a value of this width to the given port.
+        unsafe fn port_write(port: u16, value: Self);
+    }
+
+    mod private {
+        pub trait Sealed {}
+
+        impl Sealed for u8 {}
+        impl Sealed for u16 {}
+        impl Sealed for u32 {}
+    }
+
+    impl PortValue for u8 {
+        unsafe fn port_read(port: u16) -> u8 {
+            inb(port)
+        }
+
+        unsafe fn port_write(port: u16, value: u8) {
+            outb(port, value)
+        }
+    }
+
+    impl PortValue for u16 {
+        unsafe fn port_read(port: u16) -> u16 {
+            inw(port)
+        }
+
+        unsafe fn port_write(port: u16, value: u16) {
+            outw(port, value)
+        }
+    }
+
+    impl PortValue for u32 {
+        unsafe fn port_read(port: u16) -> u32 {
+            inl(port)
+        }
+
+        unsafe fn port_write(port: u16, value: u32) {
+            outl(port, value)
+        }
+    }
+
+    /// An I/O port that allows both reading and writing a value of type `T`.
+    #[derive(Debug)]
+    pub struct Port<T: PortValue> {
+        port: u16,
+        phantom: PhantomData<T>,
+    }
+
+    impl<T: PortValue> Port<T> {
+        /// Creates an I/O port with the given port address.
+        pub const fn new(port: u16) -> Port<T> {
+            Port {
+                port,
+                phantom: PhantomData,
+            }
+        }
+
+        /// Reads a value from the port.
+        pub unsafe fn read(&self) -> T {
+            T::port_read(self.port)
+        }
+
+        /// Writes a value to the port.
+        pub unsafe fn write(&mut self, value: T) {
+            T::port_write(self.port, value)
+        }
+    }
+
+    /// An I/O port that only allows reading a value of type `T`.
+    #[derive(Debug)]
+    pub struct PortReadOnly<T: PortValue> {
+        port: u16,
+        phantom: PhantomData<T>,
+    }
+
+    impl<T: PortValue> PortReadOnly<T> {
+        /// Creates a read-only I/O port with the given port address.
+        pub const fn new(port: u16) -> PortReadOnly<T> {
+            PortReadOnly {
+                port,
+                phantom: PhantomData,
+            }
+        }
+
+        /// Reads a value from the port.
+        pub unsafe fn read(&self) -> T {
+            T::port_read(self.port)
+        }
+    }
+
+    /// An I/O port that only allows writing a value of type `T`.
+    #[derive(Debug)]
+    pub struct PortWriteOnly<T: PortValue> {
+        port: u16,
+        phantom: PhantomData<T>,
+    }
+
+    impl<T: PortValue> PortWriteOnly<T> {
+        /// Creates a write-only I/O port with the given port address.
+        pub const fn new(port: u16) -> PortWriteOnly<T> {
+            PortWriteOnly {
+                port,
+                phantom: PhantomData,
+            }
+        }
+
+        /// Writes a value to the port.
+        pub unsafe fn write(&mut self, value: T) {
+            T::port_write(self.port, value)
+        }
+    }
+
     /// Write 8 bits to I/O port.
     pub unsafe fn outb(port: u16, val: u8) {
         asm!("outb %al, %dx" :: "{dx}"(port), "{al}"(val));